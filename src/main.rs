@@ -1,15 +1,66 @@
 use std::{
-    collections::HashMap,
-    fs::{copy, create_dir_all, read_dir, File, ReadDir},
-    io::{Read, Result},
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::{copy, create_dir_all, metadata, read_dir, write, File},
+    hash::Hasher as _,
+    io::{Error, ErrorKind, Read, Result},
     path::{Path, PathBuf},
     process::exit,
-    sync::atomic::{AtomicUsize, Ordering},
 };
 
-use clap::Parser;
-use md5::Context;
+use clap::{Parser, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use rayon::prelude::*;
+use serde::Serialize;
+use siphasher::sip128::{Hasher128, SipHasher13};
+
+const PARTIAL_SIZE: u64 = 4 * 1024;
+
+/// Digest used to tell files apart. None of these need to be
+/// cryptographically strong: we only care about equality detection.
+#[derive(Clone, Copy, ValueEnum)]
+enum HashAlgorithm {
+    /// SipHash-1-3, a fast 128-bit non-cryptographic hash.
+    Siphash128,
+    Md5,
+    Blake3,
+}
+
+/// Streaming hasher backing one of the [`HashAlgorithm`] variants.
+enum Hasher {
+    Siphash128(SipHasher13),
+    Md5(md5::Context),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Siphash128 => Self::Siphash128(SipHasher13::new()),
+            HashAlgorithm::Md5 => Self::Md5(md5::Context::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn consume(&mut self, data: &[u8]) {
+        match self {
+            Self::Siphash128(hasher) => hasher.write(data),
+            Self::Md5(context) => context.consume(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Self::Siphash128(hasher) => format!("{:032x}", hasher.finish128().as_u128()),
+            Self::Md5(context) => format!("{:x}", context.compute()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
 
 #[derive(Parser)]
 #[command(version)]
@@ -25,70 +76,339 @@ struct Args {
 
     #[arg(default_value_t = false, long, short = 'R')]
     rename: bool,
+
+    #[arg(default_value = "siphash128", long, value_enum)]
+    hash: HashAlgorithm,
+
+    #[arg(default_value_t = true, long, action = clap::ArgAction::Set)]
+    verify: bool,
+
+    #[arg(long, short)]
+    manifest: Option<PathBuf>,
+
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    #[arg(default_value_t = false, long)]
+    gitignore: bool,
+
+    #[arg(long)]
+    ext: Vec<String>,
+
+    #[arg(long)]
+    mime: Vec<String>,
+
+    #[arg(default_value_t = false, long)]
+    dry_run: bool,
+}
+
+/// The filesystem operations `uniq` performs on `out_dir`, so previews and
+/// tests can run against a backend that never touches the real disk.
+trait Fs {
+    fn copy(&self, from: &Path, to: &Path) -> Result<()>;
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+}
+
+/// Backend that forwards straight to [`std::fs`].
+struct RealFs;
+
+impl Fs for RealFs {
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        copy(from, to).map(|_| ())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        create_dir_all(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+}
+
+/// Backend that writes nothing, only remembering the paths it was asked to
+/// create so collision detection still behaves as it would on disk.
+#[derive(Default)]
+struct InMemoryFs {
+    written: RefCell<HashSet<PathBuf>>,
+}
+
+impl Fs for InMemoryFs {
+    fn copy(&self, _from: &Path, to: &Path) -> Result<()> {
+        self.written.borrow_mut().insert(to.to_path_buf());
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.written.borrow_mut().insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.written.borrow().contains(path) || path.exists()
+    }
 }
 
-struct FileIterator {
-    dirs: Vec<ReadDir>,
+/// Decides which directories are descended into and which files are kept,
+/// consulted by [`collect_entries`] before anything is `read_dir`'d or hashed.
+struct Filter {
+    root: PathBuf,
+    exclude: GlobSet,
+    gitignore: Option<Gitignore>,
+    exts: Vec<String>,
+    mimes: Vec<String>,
 }
 
-impl FileIterator {
-    fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+impl Filter {
+    fn new(root: &Path, args: &Args) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+
+        for pattern in &args.exclude {
+            builder.add(Glob::new(pattern).map_err(|error| Error::new(ErrorKind::InvalidInput, error))?);
+        }
+
+        let exclude = builder
+            .build()
+            .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+
+        let gitignore = if args.gitignore {
+            let mut builder = GitignoreBuilder::new(root);
+            builder.add(root.join(".gitignore"));
+            Some(
+                builder
+                    .build()
+                    .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?,
+            )
+        } else {
+            None
+        };
+
         Ok(Self {
-            dirs: vec![read_dir(path)?],
+            root: root.to_path_buf(),
+            exclude,
+            gitignore,
+            exts: args.ext.iter().map(|ext| ext.to_lowercase()).collect(),
+            mimes: args.mime.clone(),
         })
     }
+
+    /// Whether `path` is matched by an `--exclude` glob or by `.gitignore`.
+    fn excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+
+        if self.exclude.is_match(relative)
+            || path
+                .file_name()
+                .is_some_and(|name| self.exclude.is_match(name))
+        {
+            return true;
+        }
+
+        match &self.gitignore {
+            Some(gitignore) => gitignore.matched(relative, is_dir).is_ignore(),
+            None => false,
+        }
+    }
+
+    /// Whether the directory `path` should be descended into.
+    fn accept_dir(&self, path: &Path) -> bool {
+        !self.excluded(path, true)
+    }
+
+    /// Whether the file `path` should be hashed, honouring `--ext`/`--mime`.
+    fn accept_file(&self, path: &Path) -> bool {
+        if self.excluded(path, false) {
+            return false;
+        }
+
+        if !self.exts.is_empty() {
+            let matches = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| self.exts.iter().any(|wanted| wanted == &ext.to_lowercase()));
+
+            if !matches {
+                return false;
+            }
+        }
+
+        if !self.mimes.is_empty() {
+            let guess = mime_guess::from_path(path).first_raw().unwrap_or("");
+
+            if !self.mimes.iter().any(|wanted| guess.starts_with(wanted)) {
+                return false;
+            }
+        }
+
+        true
+    }
 }
 
-impl Iterator for FileIterator {
-    type Item = Result<PathBuf>;
+/// One hash group in the `--manifest` output: the file that was kept, the
+/// name it was emitted under in `out_dir`, and the sources it subsumes.
+#[derive(Serialize)]
+struct ManifestEntry {
+    kept: PathBuf,
+    output: Option<String>,
+    duplicates: Vec<PathBuf>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let dir = self.dirs.last_mut()?;
+/// Recursively collect every accepted file under `dir` together with its
+/// length, splitting both the `read_dir` scan and the `stat` work across the
+/// rayon pool: files are `stat`'d in parallel while subdirectories recurse in
+/// parallel. Recursion mirrors the (shallow) nesting of real trees.
+fn collect_entries(dir: &Path, filter: &Filter) -> Result<Vec<(u64, PathBuf)>> {
+    let mut subdirs = Vec::new();
+    let mut files = Vec::new();
 
-            if let Some(entry) = dir.next() {
-                match entry {
-                    Ok(entry) => {
-                        let path = entry.path();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
 
-                        if path.is_dir() {
-                            match read_dir(path) {
-                                Ok(dir) => {
-                                    self.dirs.push(dir);
-                                }
-                                Err(error) => return Some(Err(error)),
-                            }
-                        } else {
-                            return Some(Ok(path));
-                        }
-                    }
-                    Err(error) => return Some(Err(error)),
-                }
-            } else {
-                self.dirs.pop();
+        if path.is_dir() {
+            if filter.accept_dir(&path) {
+                subdirs.push(path);
             }
+        } else if filter.accept_file(&path) {
+            files.push(path);
         }
     }
+
+    let (subdirs, files) = rayon::join(
+        || {
+            subdirs
+                .par_iter()
+                .map(|path| collect_entries(path, filter))
+                .collect::<Result<Vec<_>>>()
+        },
+        || {
+            files
+                .par_iter()
+                .map(|path| Ok((metadata(path)?.len(), path.clone())))
+                .collect::<Result<Vec<_>>>()
+        },
+    );
+
+    let mut entries = files?;
+
+    for mut child in subdirs? {
+        entries.append(&mut child);
+    }
+
+    Ok(entries)
 }
 
-fn hash<P: AsRef<Path>>(path: P) -> Result<(P, String)> {
+/// A group of files that are indistinguishable so far.
+///
+/// Files are first bucketed by length; `full` is only filled in for buckets
+/// that had to be disambiguated by hashing, so a length (or `(len, partial)`
+/// pair) that is unique never triggers a full-file read while grouping. A
+/// full hash is still computed later for such a file when it is renamed.
+struct FileInfo {
+    len: u64,
+    partial: Option<String>,
+    full: Option<String>,
+    paths: Vec<PathBuf>,
+}
+
+/// Hash `path`, reading at most `limit` bytes (`None` for the whole file).
+fn digest<P: AsRef<Path>>(
+    path: P,
+    limit: Option<u64>,
+    algorithm: HashAlgorithm,
+) -> Result<String> {
     let mut file = File::open(&path)?;
-    let mut buffer = [0; 4 * 1024];
-    let mut context = Context::new();
+    let mut buffer = [0; PARTIAL_SIZE as usize];
+    let mut hasher = Hasher::new(algorithm);
+    let mut remaining = limit.unwrap_or(u64::MAX);
 
-    loop {
-        let read = file.read(&mut buffer)?;
+    while remaining > 0 {
+        let want = remaining.min(buffer.len() as u64) as usize;
+        let read = file.read(&mut buffer[..want])?;
 
         if read > 0 {
-            context.consume(&buffer[..read]);
+            hasher.consume(&buffer[..read]);
+            remaining -= read as u64;
         } else {
             break;
         }
     }
 
-    let hash = format!("{:x}", context.compute());
+    Ok(hasher.finish())
+}
+
+/// Read from `file` until `buffer` is full or the end of the file is reached,
+/// returning the number of bytes actually read.
+fn fill<R: Read>(file: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        let read = file.read(&mut buffer[filled..])?;
+
+        if read == 0 {
+            break;
+        }
+
+        filled += read;
+    }
+
+    Ok(filled)
+}
+
+/// Whether `a` and `b` have byte-for-byte identical contents.
+fn same_contents<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> Result<bool> {
+    if metadata(&a)?.len() != metadata(&b)?.len() {
+        return Ok(false);
+    }
+
+    let mut a = File::open(&a)?;
+    let mut b = File::open(&b)?;
+    let mut buffer_a = [0; PARTIAL_SIZE as usize];
+    let mut buffer_b = [0; PARTIAL_SIZE as usize];
+
+    loop {
+        let read_a = fill(&mut a, &mut buffer_a)?;
+        let read_b = fill(&mut b, &mut buffer_b)?;
+
+        if read_a != read_b || buffer_a[..read_a] != buffer_b[..read_b] {
+            return Ok(false);
+        }
+
+        if read_a == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Split a hash group into clusters whose members are byte-for-byte equal,
+/// guarding against the tool dropping a genuinely unique file to a collision.
+fn verify_paths(paths: Vec<PathBuf>) -> Result<Vec<Vec<PathBuf>>> {
+    let mut clusters: Vec<Vec<PathBuf>> = Vec::new();
 
-    Ok((path, hash))
+    'paths: for path in paths {
+        for cluster in &mut clusters {
+            if same_contents(&cluster[0], &path)? {
+                cluster.push(path);
+                continue 'paths;
+            }
+        }
+
+        clusters.push(vec![path]);
+    }
+
+    Ok(clusters)
+}
+
+/// Hash of `path`'s full contents, reusing `cached` when it is already known.
+fn full_hash<P: AsRef<Path>>(
+    path: P,
+    cached: &Option<String>,
+    algorithm: HashAlgorithm,
+) -> Result<String> {
+    match cached {
+        Some(hash) => Ok(hash.clone()),
+        None => digest(path, None, algorithm),
+    }
 }
 
 fn main() -> Result<()> {
@@ -115,49 +435,137 @@ fn main() -> Result<()> {
 
     let out_dir = args
         .out_dir
+        .clone()
         .unwrap_or_else(|| args.work_dir.with_extension("uniq"));
 
-    create_dir_all(&out_dir)?;
+    let fs: Box<dyn Fs> = if args.dry_run {
+        Box::new(InMemoryFs::default())
+    } else {
+        Box::new(RealFs)
+    };
+
+    fs.create_dir_all(&out_dir)?;
+
+    eprintln!("Collecting files...");
+
+    let filter = Filter::new(&args.root, &args)?;
+    let entries = collect_entries(&args.root, &filter)?;
+
+    eprintln!(
+        "Collected {} files, hashing what needs hashing now...",
+        entries.len()
+    );
+
+    let mut by_len: HashMap<u64, Vec<PathBuf>> = HashMap::new();
 
-    eprintln!("Hashing files...");
+    for (len, path) in entries {
+        by_len.entry(len).or_default().push(path);
+    }
 
-    let files = AtomicUsize::new(0);
+    // Any length shared by more than one file needs a partial hash; only
+    // files that still collide on `(len, partial)` get a full hash.
+    let mut groups = by_len
+        .into_par_iter()
+        .map(|(len, paths)| {
+            if paths.len() == 1 {
+                return Ok(vec![FileInfo {
+                    len,
+                    partial: None,
+                    full: None,
+                    paths,
+                }]);
+            }
 
-    let file_hashes = FileIterator::new(&args.root)?
-        .par_bridge()
-        .map(|path| path.and_then(hash))
-        .inspect(|_| {
-            let files = files.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut by_partial: HashMap<String, Vec<PathBuf>> = HashMap::new();
 
-            if files % 100 == 0 {
-                eprintln!("...{files}");
+            for path in paths {
+                let partial = digest(&path, Some(PARTIAL_SIZE), args.hash)?;
+                by_partial.entry(partial).or_default().push(path);
             }
+
+            let mut infos = Vec::new();
+
+            for (partial, paths) in by_partial {
+                if paths.len() == 1 {
+                    infos.push(FileInfo {
+                        len,
+                        partial: Some(partial),
+                        full: None,
+                        paths,
+                    });
+                    continue;
+                }
+
+                let mut by_full: HashMap<String, Vec<PathBuf>> = HashMap::new();
+
+                for path in paths {
+                    let full = digest(&path, None, args.hash)?;
+                    by_full.entry(full).or_default().push(path);
+                }
+
+                for (full, paths) in by_full {
+                    infos.push(FileInfo {
+                        len,
+                        partial: Some(partial.clone()),
+                        full: Some(full),
+                        paths,
+                    });
+                }
+            }
+
+            Ok(infos)
         })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
         .collect::<Vec<_>>();
 
-    eprintln!(
-        "Hashed {} files and doing the real work now...",
-        file_hashes.len()
-    );
-
-    let mut hashed_files = HashMap::new();
+    // A hash collision would otherwise silently merge two unique files and
+    // drop one; confirm equality byte-for-byte before trusting a group.
+    if args.verify {
+        groups = groups
+            .into_par_iter()
+            .map(|info| {
+                if info.paths.len() < 2 {
+                    return Ok(vec![info]);
+                }
 
-    for file_hash in file_hashes {
-        let (path, hash) = file_hash?;
-        hashed_files.entry(hash).or_insert_with(Vec::new).push(path);
+                let len = info.len;
+                let partial = info.partial;
+                let full = info.full;
+
+                Ok(verify_paths(info.paths)?
+                    .into_iter()
+                    .map(|paths| FileInfo {
+                        len,
+                        partial: partial.clone(),
+                        full: full.clone(),
+                        paths,
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
     }
 
+    eprintln!("Hashed files and doing the real work now...");
+
     let mut ignored_files = Vec::new();
+    let mut manifest = Vec::new();
 
-    for (hash, same_files) in &mut hashed_files {
-        same_files.sort();
+    for group in &mut groups {
+        group.paths.sort();
 
-        let mut working_files = same_files
+        let mut working_files = group
+            .paths
             .iter()
             .filter(|file| file.starts_with(&args.work_dir));
 
         if let Some(working_file) = working_files.next() {
-            if let Some(existing_file) = same_files
+            if let Some(existing_file) = group
+                .paths
                 .iter()
                 .find(|file| !file.starts_with(&args.work_dir))
             {
@@ -166,10 +574,22 @@ fn main() -> Result<()> {
                     working_file.strip_prefix(&args.work_dir).unwrap().display(),
                     existing_file.strip_prefix(&args.root).unwrap().display()
                 ));
+
+                if args.manifest.is_some() {
+                    let mut duplicates = vec![working_file.clone()];
+                    duplicates.extend(working_files.cloned());
+
+                    manifest.push(ManifestEntry {
+                        kept: existing_file.clone(),
+                        output: None,
+                        duplicates,
+                    });
+                }
             } else {
                 let mut file_name = working_file.file_name().unwrap().to_string_lossy();
 
                 if args.rename {
+                    let hash = full_hash(working_file, &group.full, args.hash)?;
                     file_name = format!("{hash}_{file_name}").into();
                 }
 
@@ -178,7 +598,8 @@ fn main() -> Result<()> {
                 out_file.push(&out_dir);
                 out_file.push(file_name.as_ref());
 
-                if !args.rename && out_file.exists() {
+                if !args.rename && fs.exists(&out_file) {
+                    let hash = full_hash(working_file, &group.full, args.hash)?;
                     file_name = format!("{hash}_{file_name}").into();
 
                     out_file.pop();
@@ -192,15 +613,25 @@ fn main() -> Result<()> {
                     );
                 }
 
-                copy(working_file, out_file)?;
+                fs.copy(working_file, &out_file)?;
+
+                let duplicates = working_files.cloned().collect::<Vec<_>>();
 
-                working_files.for_each(|file| {
+                for file in &duplicates {
                     ignored_files.push(format!(
                         "{} = {}",
                         file.strip_prefix(&args.work_dir).unwrap().display(),
                         file_name
                     ));
-                });
+                }
+
+                if args.manifest.is_some() {
+                    manifest.push(ManifestEntry {
+                        kept: working_file.clone(),
+                        output: Some(file_name.into_owned()),
+                        duplicates,
+                    });
+                }
             }
         }
     }
@@ -211,6 +642,12 @@ fn main() -> Result<()> {
         println!("{ignored_file}");
     }
 
+    if let Some(path) = &args.manifest {
+        let json = serde_json::to_string_pretty(&manifest).map_err(Error::other)?;
+
+        write(path, json)?;
+    }
+
     eprintln!("... aaaand done :-)");
 
     Ok(())